@@ -14,12 +14,19 @@
 
 //! Route guide client.
 
-use futures::{stream, Future};
+use clap::{Parser, Subcommand, ValueEnum};
+use futures::stream::BoxStream;
+use futures::{stream, Future, StreamExt};
 use rand::seq::SliceRandom;
+use rand::Rng;
 use route::route_guide_client::RouteGuideClient;
-use route::{util, Point, Rectangle, RouteNote};
+use route::util::NodeInformation;
+use route::{util, Feature, Point, Rectangle, RouteNote, RouteSummary};
+use serde::Serialize;
+use std::time::{Duration, Instant};
 use tokio::runtime::Runtime;
-use tonic::Request;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity};
+use tonic::{Code, Request, Response, Status};
 
 trait FutureExt: Future {
     fn block_on(self, runtime: &mut Runtime) -> Self::Output;
@@ -32,99 +39,424 @@ impl<T: Future> FutureExt for T {
     }
 }
 
+/// Protocol version implemented by this client.
+///
+/// Sent with every request so the server can reject an
+/// incompatible build with a clear error instead of a mysterious decode
+/// failure.
+const PROTOCOL_VERSION: u32 = 1;
+
+/// Metadata header carrying `PROTOCOL_VERSION`.
+const VERSION_HEADER: &str = "x-routeguide-version";
+
+/// Metadata header carrying the server's node ID.
+const NODE_ID_HEADER: &str = "x-routeguide-node-id";
+
+/// Metadata header carrying the server's human-readable display name.
+const NODE_NAME_HEADER: &str = "x-routeguide-node-name";
+
+/// Route guide CLI client.
+#[derive(Parser)]
+struct Cli {
+    /// Output format for command results.
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Looks up the feature at a single point.
+    GetFeature { latitude: i32, longitude: i32 },
+    /// Lists features within a rectangle.
+    ListFeatures {
+        lo_latitude: i32,
+        lo_longitude: i32,
+        hi_latitude: i32,
+        hi_longitude: i32,
+    },
+    /// Records a randomly generated route and reports a summary.
+    RecordRoute,
+    /// Sends a scripted sequence of route notes and prints the replies.
+    RouteChat,
+}
+
+/// Result of a `get-feature` call.
+#[derive(Serialize)]
+struct FeatureResult {
+    found: bool,
+    name: String,
+    latitude: i32,
+    longitude: i32,
+}
+
+/// Result of a `record-route` call.
+#[derive(Serialize)]
+struct SummaryResult {
+    point_count: i32,
+    feature_count: i32,
+    distance: i32,
+    elapsed_time: i32,
+}
+
+/// One reply received from a `route-chat` call.
+#[derive(Serialize)]
+struct NoteResult {
+    message: String,
+    latitude: i32,
+    longitude: i32,
+}
+
+/// Prints a result either as one line of JSON or via the given text renderer.
+fn print_result<T: Serialize>(format: OutputFormat, value: &T, text: impl FnOnce(&T)) {
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string(value).unwrap()),
+        OutputFormat::Text => text(value),
+    }
+}
+
+/// Async, mid-level facade over the generated `RouteGuideClient`, hiding the
+/// raw codec/stream plumbing behind idiomatic `Future`/`Stream` types
+/// instead of a hand-rolled `Runtime` and `response.message().await` loops.
+///
+/// Methods take a `Request<T>`/streaming request rather than a bare message
+/// so callers (e.g. `Client`) can still attach metadata such as the
+/// protocol-version header and read it back off the response.
+#[derive(Clone)]
+struct RouteGuide {
+    client: RouteGuideClient<Channel>,
+}
+
+impl RouteGuide {
+    fn new(client: RouteGuideClient<Channel>) -> Self {
+        Self { client }
+    }
+
+    async fn get_feature(
+        &mut self,
+        request: impl tonic::IntoRequest<Point>,
+    ) -> Result<Response<Option<Feature>>, Status> {
+        let response = self.client.get_feature(request).await?;
+        let (metadata, feature, extensions) = response.into_parts();
+        let found = (feature.location.is_some() && !feature.name.is_empty()).then_some(feature);
+        Ok(Response::from_parts(metadata, found, extensions))
+    }
+
+    /// Returns the stream's *initial* response (headers only, no message)
+    /// alongside the stream itself, so callers can still read metadata such
+    /// as the protocol-version header off a server-streaming call.
+    async fn list_features(
+        &mut self,
+        request: impl tonic::IntoRequest<Rectangle>,
+    ) -> Result<(Response<()>, BoxStream<'static, Result<Feature, Status>>), Status> {
+        let response = self.client.list_features(request).await?;
+        let (metadata, mut stream, extensions) = response.into_parts();
+        let stream = Box::pin(async_stream::try_stream! {
+            while let Some(feature) = stream.message().await? {
+                yield feature;
+            }
+        });
+        Ok((Response::from_parts(metadata, (), extensions), stream))
+    }
+
+    async fn record_route(
+        &mut self,
+        request: impl tonic::IntoStreamingRequest<Message = Point>,
+    ) -> Result<Response<RouteSummary>, Status> {
+        self.client.record_route(request).await
+    }
+
+    /// Returns the stream's *initial* response (headers only, no message)
+    /// alongside the stream itself, so callers can still read metadata such
+    /// as the protocol-version header off a bidirectional-streaming call.
+    async fn route_chat(
+        &mut self,
+        request: impl tonic::IntoStreamingRequest<Message = RouteNote>,
+    ) -> Result<(Response<()>, BoxStream<'static, Result<RouteNote, Status>>), Status> {
+        let response = self.client.route_chat(request).await?;
+        let (metadata, mut stream, extensions) = response.into_parts();
+        let stream = Box::pin(async_stream::try_stream! {
+            while let Some(note) = stream.message().await? {
+                yield note;
+            }
+        });
+        Ok((Response::from_parts(metadata, (), extensions), stream))
+    }
+}
+
+/// Exponential backoff with jitter, used to space out reconnect/retry
+/// attempts after a transient RPC failure.
+struct Backoff {
+    current_interval: Duration,
+    max_interval: Duration,
+    multiplier: f64,
+    start: Instant,
+    max_elapsed_time: Duration,
+}
+
+impl Backoff {
+    fn new() -> Self {
+        Self {
+            current_interval: Duration::from_millis(500),
+            max_interval: Duration::from_secs(30),
+            multiplier: 1.5,
+            start: Instant::now(),
+            max_elapsed_time: Duration::from_secs(60),
+        }
+    }
+
+    /// Returns the delay to wait before the next attempt, or `None` once
+    /// `max_elapsed_time` has passed and the caller should give up.
+    fn next_backoff(&mut self) -> Option<Duration> {
+        if self.start.elapsed() >= self.max_elapsed_time {
+            return None;
+        }
+
+        let jitter = rand::thread_rng().gen_range(0.5..1.5);
+        let delay = self.current_interval.mul_f64(jitter);
+        self.current_interval = self.current_interval.mul_f64(self.multiplier).min(self.max_interval);
+        Some(delay)
+    }
+}
+
+/// Whether a failed RPC is worth retrying, as opposed to an application
+/// error that should propagate immediately.
+fn is_retriable(status: &Status) -> bool {
+    matches!(status.code(), Code::Unavailable | Code::Unknown)
+}
+
 struct Client {
-    client: RouteGuideClient<tonic::transport::Channel>,
+    endpoint: Endpoint,
+    client: RouteGuide,
     runtime: Runtime,
+    /// The server's protocol version, learned from the latest response.
+    server_version: Option<u32>,
+    /// The server's identity, learned from the latest response.
+    server_node: Option<NodeInformation>,
 }
 
 impl Client {
     fn new<T: Into<String>>(addr: T) -> Self {
         let mut runtime = Runtime::new().unwrap();
-        let client = RouteGuideClient::connect(addr.into())
-            .block_on(&mut runtime)
-            .expect("");
 
-        Self { client, runtime }
-    }
+        let cert = std::fs::read("certs/client.pem").expect("Failed to read client cert");
+        let key = std::fs::read("certs/client.key").expect("Failed to read client key");
+        let server_ca_cert =
+            std::fs::read("certs/server_ca.pem").expect("Failed to read server CA cert");
 
-    fn get_feature(&mut self, point: Point) {
-        let feature = self
-            .client
-            .get_feature(Request::new(point.clone()))
-            .block_on(&mut self.runtime)
-            .expect("Failed to get feature")
-            .into_inner();
+        let tls_config = ClientTlsConfig::new()
+            .identity(Identity::from_pem(cert, key))
+            .ca_certificate(Certificate::from_pem(server_ca_cert));
+
+        let endpoint = Endpoint::from_shared(addr.into())
+            .expect("Invalid server address")
+            .tls_config(tls_config)
+            .expect("Failed to configure TLS");
+
+        let channel = Self::connect_with_retry(&endpoint, &mut runtime);
+        let client = RouteGuide::new(RouteGuideClient::new(channel));
 
-        if feature.location.is_none() {
-            eprintln!("Server returns incomplete feature.");
-            return;
+        Self {
+            endpoint,
+            client,
+            runtime,
+            server_version: None,
+            server_node: None,
         }
+    }
 
-        if feature.name.is_empty() {
-            println!("No feature found at {}", point);
-            return;
+    /// Connects to `endpoint`, retrying with exponential backoff on failure
+    /// until `max_elapsed_time` is exceeded.
+    fn connect_with_retry(endpoint: &Endpoint, runtime: &mut Runtime) -> Channel {
+        let mut backoff = Backoff::new();
+        loop {
+            match endpoint.connect().block_on(runtime) {
+                Ok(channel) => return channel,
+                Err(e) => match backoff.next_backoff() {
+                    Some(delay) => {
+                        eprintln!("failed to connect ({}), retrying in {:?}", e, delay);
+                        std::thread::sleep(delay);
+                    }
+                    None => panic!("Failed to connect: {}", e),
+                },
+            }
         }
+    }
 
-        println!("Found feature {} at {}", feature.name, point);
+    /// Reconnects the underlying channel, retrying with backoff.
+    fn reconnect(&mut self) {
+        self.client = RouteGuide::new(RouteGuideClient::new(Self::connect_with_retry(
+            &self.endpoint,
+            &mut self.runtime,
+        )));
     }
 
-    fn list_features(&mut self, rect: Rectangle) {
-        println!(
-            "Searching features between {} and {}",
-            rect.lo.as_ref().unwrap(),
-            rect.hi.as_ref().unwrap(),
-        );
+    /// Runs `make_call` against the current connection, retrying with
+    /// backoff and reconnecting on retriable `tonic::Status` errors.
+    /// Application errors are returned immediately.
+    ///
+    /// `make_call` must be safe to invoke more than once: used for unary
+    /// calls and for `record_route`, whose client-streaming request is
+    /// rebuilt from an owned `Vec<Point>` on every attempt. A
+    /// server-streaming RPC can't be replayed once the server has started
+    /// producing messages, so `list_features` and `route_chat` go around
+    /// this helper and make a single attempt instead.
+    fn call_with_retry<T, Fut>(
+        &mut self,
+        mut make_call: impl FnMut(&mut RouteGuide) -> Fut,
+    ) -> Result<T, Status>
+    where
+        Fut: Future<Output = Result<T, Status>>,
+    {
+        let mut backoff = Backoff::new();
+        loop {
+            match make_call(&mut self.client).block_on(&mut self.runtime) {
+                Ok(value) => return Ok(value),
+                Err(status) if is_retriable(&status) => match backoff.next_backoff() {
+                    Some(delay) => {
+                        eprintln!("RPC failed ({}), retrying in {:?}", status, delay);
+                        std::thread::sleep(delay);
+                        self.reconnect();
+                    }
+                    None => return Err(status),
+                },
+                Err(status) => return Err(status),
+            }
+        }
+    }
 
-        let mut response = self
-            .client
-            .list_features(rect)
-            .block_on(&mut self.runtime)
-            .expect("Failed to list features")
-            .into_inner();
+    /// The server's negotiated protocol version, if a response has been
+    /// received yet, so callers can branch behavior.
+    fn server_version(&self) -> Option<u32> {
+        self.server_version
+    }
 
-        loop {
-            match response.message().block_on(&mut self.runtime) {
-                Ok(Some(feature)) => {
-                    let location = feature.location.as_ref().unwrap();
-                    println!("Found feature {} at {}", feature.name, location);
+    /// The server's identity, if a response has been received yet.
+    fn server_node(&self) -> Option<&NodeInformation> {
+        self.server_node.as_ref()
+    }
+
+    /// Wraps a request with the client's protocol version header.
+    fn versioned_request<T>(message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        request
+            .metadata_mut()
+            .insert(VERSION_HEADER, PROTOCOL_VERSION.to_string().parse().unwrap());
+        request
+    }
+
+    /// Records the server's protocol version and identity from a response's
+    /// metadata.
+    fn record_server_version<T>(&mut self, response: &tonic::Response<T>) {
+        if let Some(value) = response.metadata().get(VERSION_HEADER) {
+            if let Ok(version) = value.to_str().unwrap_or_default().parse() {
+                self.server_version = Some(version);
+
+                if let Some(node_id) = response
+                    .metadata()
+                    .get(NODE_ID_HEADER)
+                    .and_then(|v| v.to_str().ok())
+                {
+                    let display_name = response
+                        .metadata()
+                        .get(NODE_NAME_HEADER)
+                        .and_then(|v| v.to_str().ok())
+                        .unwrap_or_default()
+                        .to_string();
+
+                    self.server_node = Some(NodeInformation {
+                        node_id: node_id.to_string(),
+                        display_name,
+                        protocol_version: version,
+                    });
                 }
-                Ok(None) => break,
-                Err(e) => panic!("Failed to list features: {:?}", e),
             }
         }
-        println!("List features successfully!");
     }
 
-    fn record_route(&mut self) {
+    fn get_feature(&mut self, point: Point) -> FeatureResult {
+        let response = self
+            .call_with_retry(|client| client.get_feature(Self::versioned_request(point.clone())))
+            .expect("Failed to get feature");
+        self.record_server_version(&response);
+        let feature = response.into_inner();
+
+        FeatureResult {
+            found: feature.is_some(),
+            name: feature.map(|f| f.name).unwrap_or_default(),
+            latitude: point.latitude,
+            longitude: point.longitude,
+        }
+    }
+
+    fn list_features(&mut self, rect: Rectangle) -> Vec<FeatureResult> {
+        let (response, stream) = self
+            .client
+            .list_features(Self::versioned_request(rect))
+            .block_on(&mut self.runtime)
+            .expect("Failed to list features");
+        self.record_server_version(&response);
+
+        let features = stream
+            .collect::<Vec<_>>()
+            .block_on(&mut self.runtime)
+            .into_iter()
+            .collect::<Result<Vec<_>, Status>>()
+            .expect("Failed to list features");
+
+        features
+            .into_iter()
+            .map(|feature| {
+                let location = feature.location.as_ref().unwrap();
+                FeatureResult {
+                    found: true,
+                    name: feature.name,
+                    latitude: location.latitude,
+                    longitude: location.longitude,
+                }
+            })
+            .collect()
+    }
+
+    fn record_route(&mut self) -> (Vec<Point>, SummaryResult) {
         let db = util::load_database();
         let mut rng = rand::thread_rng();
 
         let points: Vec<_> = (0..10)
             .map(|_| {
                 let feature = db.feature.choose(&mut rng).unwrap();
-                let location = feature.location.clone().unwrap();
-                println!("Visiting {}", location);
-                location
+                feature.location.clone().unwrap()
             })
             .collect();
 
-        let request = Request::new(stream::iter(points));
-
-        let sum = self
-            .client
-            .record_route(request)
-            .block_on(&mut self.runtime)
-            .unwrap()
-            .into_inner();
+        let response = self
+            .call_with_retry(|client| {
+                client.record_route(Self::versioned_request(stream::iter(points.clone())))
+            })
+            .unwrap();
+        self.record_server_version(&response);
+        let sum = response.into_inner();
 
-        println!("Finished trip, route summary:");
-        println!("\tVisited {} points", sum.point_count);
-        println!("\tPassed {} features", sum.feature_count);
-        println!("\tTravelled {} meters", sum.distance);
-        println!("\tTook {} seconds", sum.elapsed_time);
+        (
+            points,
+            SummaryResult {
+                point_count: sum.point_count,
+                feature_count: sum.feature_count,
+                distance: sum.distance,
+                elapsed_time: sum.elapsed_time,
+            },
+        )
     }
 
-    fn route_chat(&mut self) {
+    fn route_chat(&mut self) -> (Vec<RouteNote>, Vec<NoteResult>) {
         let notes: Vec<_> = vec![
             ("First message", 0, 0),
             ("Second message", 0, 1),
@@ -132,73 +464,129 @@ impl Client {
             ("Fourth message", 0, 0),
         ]
         .iter()
-        .map(|(msg, lat, lon)| {
-            println!("Sending message {} at ({},{})", msg, lat, lon);
-            RouteNote {
-                location: Some(Point {
-                    latitude: *lat,
-                    longitude: *lon,
-                }),
-                message: msg.to_string(),
-            }
+        .map(|(msg, lat, lon)| RouteNote {
+            location: Some(Point {
+                latitude: *lat,
+                longitude: *lon,
+            }),
+            message: msg.to_string(),
         })
         .collect();
 
-        let request = Request::new(stream::iter(notes));
-
-        let mut response = self
+        let (response, stream) = self
             .client
-            .route_chat(request)
+            .route_chat(Self::versioned_request(stream::iter(notes.clone())))
             .block_on(&mut self.runtime)
-            .expect("Failed to route chat")
-            .into_inner();
+            .expect("Failed to route chat");
+        self.record_server_version(&response);
 
-        loop {
-            match response.message().block_on(&mut self.runtime) {
-                Ok(Some(note)) => {
-                    let location = note.location.as_ref().unwrap();
-                    println!("Got message {} at {}", note.message, location);
+        let received = stream
+            .collect::<Vec<_>>()
+            .block_on(&mut self.runtime)
+            .into_iter()
+            .collect::<Result<Vec<_>, Status>>()
+            .expect("Failed to route chat");
+
+        let results = received
+            .into_iter()
+            .map(|note| {
+                let location = note.location.as_ref().unwrap();
+                NoteResult {
+                    message: note.message,
+                    latitude: location.latitude,
+                    longitude: location.longitude,
                 }
-                Ok(None) => break,
-                Err(e) => panic!("Failed to route chat: {:?}", e),
-            }
-        }
+            })
+            .collect();
+        (notes, results)
     }
 }
 
 fn main() {
+    let cli = Cli::parse();
     let mut client = Client::new("http://127.0.0.1:8980");
 
-    println!("Get Feature:");
-    // Looking for a valid feature
-    client.get_feature(Point {
-        latitude: 409146138,
-        longitude: -746188906,
-    });
-    // Feature missing.
-    client.get_feature(Point {
-        latitude: 0,
-        longitude: 0,
-    });
-
-    println!();
-    println!("List features:");
-    client.list_features(Rectangle {
-        lo: Some(Point {
-            latitude: 400000000,
-            longitude: -750000000,
-        }),
-        hi: Some(Point {
-            latitude: 420000000,
-            longitude: -730000000,
-        }),
-    });
-
-    println!();
-    println!("Record route:");
-    client.record_route();
-
-    println!();
-    println!("Route chat:");
-    client.route_chat();
+    match cli.command {
+        Command::GetFeature {
+            latitude,
+            longitude,
+        } => {
+            let point = Point {
+                latitude,
+                longitude,
+            };
+            let result = client.get_feature(point.clone());
+            print_result(cli.format, &result, |r| {
+                if r.found {
+                    println!("Found feature {} at {}", r.name, point);
+                } else {
+                    println!("No feature found at {}", point);
+                }
+            });
+        }
+        Command::ListFeatures {
+            lo_latitude,
+            lo_longitude,
+            hi_latitude,
+            hi_longitude,
+        } => {
+            let rect = Rectangle {
+                lo: Some(Point {
+                    latitude: lo_latitude,
+                    longitude: lo_longitude,
+                }),
+                hi: Some(Point {
+                    latitude: hi_latitude,
+                    longitude: hi_longitude,
+                }),
+            };
+            let results = client.list_features(rect);
+            for result in &results {
+                print_result(cli.format, result, |r| {
+                    println!("Found feature {} at ({}, {})", r.name, r.latitude, r.longitude);
+                });
+            }
+        }
+        Command::RecordRoute => {
+            let (points, summary) = client.record_route();
+            if cli.format == OutputFormat::Text {
+                for point in &points {
+                    println!("Visiting {}", point);
+                }
+            }
+            print_result(cli.format, &summary, |s| {
+                println!("Finished trip, route summary:");
+                println!("\tVisited {} points", s.point_count);
+                println!("\tPassed {} features", s.feature_count);
+                println!("\tTravelled {} meters", s.distance);
+                println!("\tTook {} seconds", s.elapsed_time);
+            });
+        }
+        Command::RouteChat => {
+            let (notes, results) = client.route_chat();
+            if cli.format == OutputFormat::Text {
+                for note in &notes {
+                    let location = note.location.as_ref().unwrap();
+                    println!(
+                        "Sending message {} at ({},{})",
+                        note.message, location.latitude, location.longitude
+                    );
+                }
+            }
+            for result in &results {
+                print_result(cli.format, result, |r| {
+                    println!("Got message {} at ({}, {})", r.message, r.latitude, r.longitude);
+                });
+            }
+        }
+    }
+
+    if cli.format == OutputFormat::Text {
+        if let Some(version) = client.server_version() {
+            println!("Server protocol version: {}", version);
+        }
+        if let Some(node) = client.server_node() {
+            println!("Connected to server node {}", node.node_id);
+        }
+    }
 }
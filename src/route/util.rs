@@ -16,6 +16,7 @@
 
 use crate::{FeatureDatabase, Point, Rectangle};
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
 use std::hash::{Hash, Hasher};
 use std::path::PathBuf;
@@ -79,7 +80,7 @@ pub fn calc_distance(start: &Point, end: &Point) -> i32 {
 
 impl Hash for Point {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        self.longitude.hash(state);
+        self.latitude.hash(state);
         self.longitude.hash(state);
     }
 }
@@ -92,6 +93,47 @@ impl Display for Point {
     }
 }
 
+/// Identity of a peer on the other end of a mutual-TLS connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeInformation {
+    /// Stable identifier derived from the peer's public key.
+    pub node_id: String,
+    /// Human-readable name advertised by the peer.
+    pub display_name: String,
+    /// Protocol version advertised by the peer.
+    pub protocol_version: u32,
+}
+
+/// Derives a stable node ID from a certificate's DER bytes: the hex-encoded
+/// SHA-256 hash of its subject public key (SPKI), so that re-issuing or
+/// re-encoding a certificate for the same key yields the same ID.
+///
+/// Takes raw DER, matching what `tonic`/`rustls` hand back from
+/// `Request::peer_certs()` on an established mTLS connection, and returns
+/// `None` rather than panicking since that certificate comes from the
+/// remote peer. Use `node_id_from_cert_pem` for a certificate file read
+/// from disk.
+pub fn node_id_from_cert(cert_der: &[u8]) -> Option<String> {
+    use sha2::{Digest, Sha256};
+    use x509_parser::certificate::X509Certificate;
+    use x509_parser::prelude::FromDer;
+
+    let (_, cert) = X509Certificate::from_der(cert_der).ok()?;
+
+    let digest = Sha256::digest(cert.tbs_certificate.subject_pki.raw);
+    Some(digest.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Like `node_id_from_cert`, but for PEM-encoded certificate bytes, e.g. a
+/// certificate file read from disk at startup, where a malformed cert
+/// should fail fast rather than be silently ignored.
+pub fn node_id_from_cert_pem(cert_pem: &[u8]) -> String {
+    use x509_parser::pem::parse_x509_pem;
+
+    let (_, pem) = parse_x509_pem(cert_pem).expect("malformed certificate");
+    node_id_from_cert(&pem.contents).expect("malformed certificate")
+}
+
 #[derive(Debug, Deserialize)]
 struct DB {
     feature: Vec<Feature>,
@@ -111,19 +153,22 @@ struct Location {
 
 /// Gets the default features file.
 #[inline]
-fn get_default_features_file() -> PathBuf {
+pub fn get_default_features_file() -> PathBuf {
     let dir = env!("CARGO_MANIFEST_DIR");
     let path = PathBuf::from(dir).join("data/route_guide_db.json");
     assert!(path.exists());
     path
 }
 
-/// Parses the JSON input file containing the list of features.
-#[inline]
-pub fn load_database() -> FeatureDatabase {
-    let file = get_default_features_file();
-    let file = std::fs::File::open(file).unwrap();
-    let db: DB = serde_json::from_reader(file).unwrap();
+/// Parses the JSON input file at `path` containing the list of features.
+///
+/// Returns an error instead of panicking, so a caller reloading the
+/// database live (e.g. on `SIGHUP`) can reject a malformed file and keep
+/// serving the previous one.
+pub fn try_load_database(path: &std::path::Path) -> Result<FeatureDatabase, std::io::Error> {
+    let file = std::fs::File::open(path)?;
+    let db: DB = serde_json::from_reader(file)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
 
     let feature = db
         .feature
@@ -137,5 +182,104 @@ pub fn load_database() -> FeatureDatabase {
         })
         .collect();
 
-    FeatureDatabase { feature }
+    Ok(FeatureDatabase { feature })
+}
+
+/// Parses the default features file, panicking on failure.
+///
+/// Used at startup, where there is no previous database to fall back to.
+#[inline]
+pub fn load_database() -> FeatureDatabase {
+    try_load_database(&get_default_features_file()).unwrap()
+}
+
+/// Size of one spatial-index grid cell, in the same 1e7-scaled integer
+/// coordinates used by `Point` (roughly 0.1 degree, ~11km at the equator).
+const GRID_CELL_SIZE: i32 = 1_000_000;
+
+fn grid_cell(point: &Point) -> (i32, i32) {
+    (
+        point.latitude.div_euclid(GRID_CELL_SIZE),
+        point.longitude.div_euclid(GRID_CELL_SIZE),
+    )
+}
+
+/// Spatial index over a `FeatureDatabase`, replacing a linear scan with an
+/// exact point lookup and a coarse grid for range queries.
+#[derive(Debug)]
+pub struct FeatureIndex {
+    by_point: HashMap<Point, crate::routeguide::Feature>,
+    grid: HashMap<(i32, i32), Vec<Point>>,
+}
+
+impl FeatureIndex {
+    fn build(database: &FeatureDatabase) -> Self {
+        let mut by_point = HashMap::new();
+        let mut grid: HashMap<(i32, i32), Vec<Point>> = HashMap::new();
+
+        for feature in &database.feature {
+            if let Some(location) = feature.location.clone() {
+                grid.entry(grid_cell(&location))
+                    .or_default()
+                    .push(location.clone());
+                by_point.insert(location, feature.clone());
+            }
+        }
+
+        Self { by_point, grid }
+    }
+
+    /// Exact lookup of the feature located at `point`, if any.
+    pub fn get(&self, point: &Point) -> Option<&crate::routeguide::Feature> {
+        self.by_point.get(point)
+    }
+
+    /// Whether any feature is located exactly at `point`.
+    pub fn contains(&self, point: &Point) -> bool {
+        self.by_point.contains_key(point)
+    }
+
+    /// Features whose location falls within `rect`. Only grid cells
+    /// overlapping the rectangle's bounding box are scanned before the
+    /// precise `in_range` check, instead of every feature.
+    pub fn query(&self, rect: &Rectangle) -> Vec<&crate::routeguide::Feature> {
+        use std::cmp::{max, min};
+
+        let lo = rect.lo.as_ref().unwrap();
+        let hi = rect.hi.as_ref().unwrap();
+
+        let lat_lo = min(lo.latitude, hi.latitude).div_euclid(GRID_CELL_SIZE);
+        let lat_hi = max(lo.latitude, hi.latitude).div_euclid(GRID_CELL_SIZE);
+        let lon_lo = min(lo.longitude, hi.longitude).div_euclid(GRID_CELL_SIZE);
+        let lon_hi = max(lo.longitude, hi.longitude).div_euclid(GRID_CELL_SIZE);
+
+        let mut matches = Vec::new();
+        for cell_lat in lat_lo..=lat_hi {
+            for cell_lon in lon_lo..=lon_hi {
+                let Some(points) = self.grid.get(&(cell_lat, cell_lon)) else {
+                    continue;
+                };
+                for point in points {
+                    if in_range(point, rect) {
+                        matches.push(&self.by_point[point]);
+                    }
+                }
+            }
+        }
+        matches
+    }
+}
+
+/// A loaded feature database paired with the spatial index built over it.
+#[derive(Debug)]
+pub struct Features {
+    pub database: FeatureDatabase,
+    pub index: FeatureIndex,
+}
+
+impl Features {
+    pub fn new(database: FeatureDatabase) -> Self {
+        let index = FeatureIndex::build(&database);
+        Self { database, index }
+    }
 }
@@ -16,35 +16,130 @@
 
 // reference: https://github.com/hyperium/tonic/blob/master/examples/src/routeguide/server.rs
 
+use arc_swap::ArcSwap;
 use futures::{Stream, StreamExt};
 use std::pin::Pin;
 use std::sync::Arc;
 use std::time::Instant;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::mpsc;
 use tonic::{Request, Response, Status, Streaming};
 
 use route::route_guide_server::{RouteGuide, RouteGuideServer};
-use route::{util, Feature, FeatureDatabase, Point, Rectangle, RouteNote, RouteSummary};
+use route::util::{Features, NodeInformation};
+use route::{util, Feature, Point, Rectangle, RouteNote, RouteSummary};
 use std::collections::HashMap;
-use tonic::transport::Server;
+use tonic::transport::{Certificate, Identity, Server, ServerTlsConfig};
+
+/// Protocol version implemented by this server.
+///
+/// Bumped whenever `route_guide.proto` changes in a way that is not
+/// backwards compatible with older clients.
+const PROTOCOL_VERSION: u32 = 1;
+
+/// Metadata header carrying the client's `PROTOCOL_VERSION`.
+const VERSION_HEADER: &str = "x-routeguide-version";
+
+/// Metadata header carrying this server's node ID, attached to every
+/// response so a client can learn who it is talking to.
+const NODE_ID_HEADER: &str = "x-routeguide-node-id";
+
+/// Metadata header carrying this server's human-readable display name.
+const NODE_NAME_HEADER: &str = "x-routeguide-node-name";
 
 #[derive(Debug)]
 struct RouteGuideService {
-    features: Arc<FeatureDatabase>,
+    /// Swapped atomically on reload so in-flight calls keep using a
+    /// consistent snapshot.
+    features: Arc<ArcSwap<Features>>,
+    /// Stable ID derived from the server's own TLS certificate.
+    node_id: String,
+    /// Human-readable name advertised to clients alongside `node_id`.
+    display_name: String,
+    /// When set, only these client node IDs may call `record_route` and
+    /// `route_chat`.
+    allowed_nodes: Option<Vec<String>>,
+}
+
+impl RouteGuideService {
+    /// Looks up the identity of the connected peer, available once mutual
+    /// TLS client auth has been negotiated for the connection.
+    fn peer_node_id<T>(request: &Request<T>) -> Option<String> {
+        let certs = request.peer_certs()?;
+        let cert = certs.first()?;
+        util::node_id_from_cert(cert.as_ref())
+    }
+
+    /// Rejects requests from a client that is not on the configured
+    /// allow-list. No-op when `allowed_nodes` is unset.
+    fn check_allowed<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        let allowed = match &self.allowed_nodes {
+            Some(allowed) => allowed,
+            None => return Ok(()),
+        };
+
+        match Self::peer_node_id(request) {
+            Some(node_id) if allowed.contains(&node_id) => Ok(()),
+            _ => Err(Status::permission_denied("node is not authorized")),
+        }
+    }
+
+    /// Rejects requests from a client whose major protocol version is newer
+    /// than ours.
+    fn check_version<T>(request: &Request<T>) -> Result<(), Status> {
+        let client_version = match request.metadata().get(VERSION_HEADER) {
+            Some(value) => value
+                .to_str()
+                .ok()
+                .and_then(|s| s.parse::<u32>().ok())
+                .ok_or_else(|| {
+                    Status::invalid_argument(format!("malformed {} header", VERSION_HEADER))
+                })?,
+            // Older clients that predate the handshake are allowed through.
+            None => return Ok(()),
+        };
+
+        if client_version > PROTOCOL_VERSION {
+            return Err(Status::failed_precondition(format!(
+                "client protocol version {} is newer than server version {}",
+                client_version, PROTOCOL_VERSION
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Stamps the response with our own version and identity so the
+    /// client can learn what it is talking to.
+    fn stamp_version<T>(&self, response: &mut Response<T>) {
+        response.metadata_mut().insert(
+            VERSION_HEADER,
+            PROTOCOL_VERSION.to_string().parse().unwrap(),
+        );
+        response
+            .metadata_mut()
+            .insert(NODE_ID_HEADER, self.node_id.parse().unwrap());
+        response
+            .metadata_mut()
+            .insert(NODE_NAME_HEADER, self.display_name.parse().unwrap());
+    }
 }
 
 #[tonic::async_trait]
 impl RouteGuide for RouteGuideService {
     async fn get_feature(&self, request: Request<Point>) -> Result<Response<Feature>, Status> {
-        match self
-            .features
-            .feature
-            .iter()
-            .find(|&f| f.location.as_ref() == Some(request.get_ref()))
-        {
-            Some(f) => Ok(Response::new(f.clone())),
-            None => Ok(Response::new(Feature::default())),
+        Self::check_version(&request)?;
+        if let Some(node_id) = Self::peer_node_id(&request) {
+            println!("get_feature from node {}", node_id);
         }
+
+        let snapshot = self.features.load();
+        let mut response = match snapshot.index.get(request.get_ref()) {
+            Some(f) => Response::new(f.clone()),
+            None => Response::new(Feature::default()),
+        };
+        self.stamp_version(&mut response);
+        Ok(response)
     }
 
     type ListFeaturesStream = mpsc::Receiver<Result<Feature, Status>>;
@@ -53,25 +148,37 @@ impl RouteGuide for RouteGuideService {
         &self,
         request: Request<Rectangle>,
     ) -> Result<Response<Self::ListFeaturesStream>, Status> {
+        Self::check_version(&request)?;
+        if let Some(node_id) = Self::peer_node_id(&request) {
+            println!("list_features from node {}", node_id);
+        }
+
         let (mut tx, rx) = mpsc::channel(4);
-        let features = self.features.clone();
+        let features = self.features.load_full();
 
         tokio::spawn(async move {
-            for f in features.feature.iter() {
-                if util::in_range(f.location.as_ref().unwrap(), request.get_ref()) {
-                    tx.send(Ok(f.clone())).await.unwrap();
-                }
+            for f in features.index.query(request.get_ref()) {
+                tx.send(Ok(f.clone())).await.unwrap();
             }
         });
 
-        Ok(Response::new(rx))
+        let mut response = Response::new(rx);
+        self.stamp_version(&mut response);
+        Ok(response)
     }
 
     async fn record_route(
         &self,
         request: Request<Streaming<Point>>,
     ) -> Result<Response<RouteSummary>, Status> {
+        Self::check_version(&request)?;
+        self.check_allowed(&request)?;
+        if let Some(node_id) = Self::peer_node_id(&request) {
+            println!("record_route from node {}", node_id);
+        }
+
         let mut stream = request.into_inner();
+        let snapshot = self.features.load_full();
 
         let mut summary = RouteSummary::default();
         let mut prev_point = None;
@@ -81,11 +188,9 @@ impl RouteGuide for RouteGuideService {
             let point = point?;
             summary.point_count += 1;
 
-            self.features.feature.iter().for_each(|f| {
-                if f.location.as_ref() == Some(&point) {
-                    summary.feature_count += 1;
-                }
-            });
+            if snapshot.index.contains(&point) {
+                summary.feature_count += 1;
+            }
 
             if let Some(ref prev) = prev_point {
                 summary.distance += util::calc_distance(prev, &point);
@@ -96,7 +201,9 @@ impl RouteGuide for RouteGuideService {
 
         summary.elapsed_time = timer.elapsed().as_secs() as i32;
 
-        Ok(Response::new(summary))
+        let mut response = Response::new(summary);
+        self.stamp_version(&mut response);
+        Ok(response)
     }
 
     type RouteChatStream =
@@ -106,6 +213,12 @@ impl RouteGuide for RouteGuideService {
         &self,
         request: Request<Streaming<RouteNote>>,
     ) -> Result<Response<Self::RouteChatStream>, Status> {
+        Self::check_version(&request)?;
+        self.check_allowed(&request)?;
+        if let Some(node_id) = Self::peer_node_id(&request) {
+            println!("route_chat from node {}", node_id);
+        }
+
         let mut notes = HashMap::new();
         let mut stream = request.into_inner();
 
@@ -124,7 +237,9 @@ impl RouteGuide for RouteGuideService {
           }
         };
 
-        Ok(Response::new(Box::pin(output)))
+        let mut response = Response::new(Box::pin(output) as Self::RouteChatStream);
+        self.stamp_version(&mut response);
+        Ok(response)
     }
 }
 
@@ -132,15 +247,58 @@ impl RouteGuide for RouteGuideService {
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let addr = "127.0.0.1:8980".parse().unwrap();
 
+    let cert = std::fs::read("certs/server.pem")?;
+    let key = std::fs::read("certs/server.key")?;
+    let client_ca_cert = std::fs::read("certs/client_ca.pem")?;
+
+    let node_info = NodeInformation {
+        node_id: util::node_id_from_cert_pem(&cert),
+        display_name: "route-guide-server".to_string(),
+        protocol_version: PROTOCOL_VERSION,
+    };
+    println!("server node id: {}", node_info.node_id);
+
+    let tls_config = ServerTlsConfig::new()
+        .identity(Identity::from_pem(cert, key))
+        .client_ca_root(Certificate::from_pem(client_ca_cert));
+
+    let features = Arc::new(ArcSwap::from_pointee(Features::new(util::load_database())));
+
+    {
+        let features = features.clone();
+        let mut hangup = signal(SignalKind::hangup())?;
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                let path = util::get_default_features_file();
+                match util::try_load_database(&path) {
+                    Ok(db) => {
+                        features.store(Arc::new(Features::new(db)));
+                        println!("reloaded feature database from {}", path.display());
+                    }
+                    Err(e) => {
+                        eprintln!("failed to reload feature database, keeping previous one: {}", e)
+                    }
+                }
+            }
+        });
+    }
+
     let route_guide = RouteGuideService {
-        features: Arc::new(util::load_database()),
+        features,
+        node_id: node_info.node_id,
+        display_name: node_info.display_name,
+        allowed_nodes: None,
     };
 
     let service = RouteGuideServer::new(route_guide);
 
     println!("listening on {}", addr);
 
-    Server::builder().add_service(service).serve(addr).await?;
+    Server::builder()
+        .tls_config(tls_config)?
+        .add_service(service)
+        .serve(addr)
+        .await?;
 
     Ok(())
 }